@@ -0,0 +1,327 @@
+// Copyright 2021 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Implement native discovery of the root block device and its GPT
+//! partition table, so the hibernate/resume path doesn't need to shell
+//! out to `rootdev` and parse trimmed stdout to find partition one.
+
+use std::fs;
+use std::fs::File;
+use std::io::Read;
+use std::io::Seek;
+use std::io::SeekFrom;
+use std::path::Path;
+use std::path::PathBuf;
+
+use anyhow::{anyhow, Context, Result};
+
+use crate::hiberutil::HibernateError;
+
+/// Size in bytes of a disk sector. GPT headers and entries are always
+/// expressed in terms of this size, which matches every block device
+/// hibernate cares about.
+const SECTOR_SIZE: u64 = 512;
+
+/// The GPT header lives in LBA 1 (LBA 0 is the protective MBR).
+const GPT_HEADER_LBA: u64 = 1;
+
+/// Magic signature identifying a valid GPT header.
+const GPT_SIGNATURE: &[u8; 8] = b"EFI PART";
+
+/// A single entry from a GPT partition table.
+#[derive(Debug, Clone)]
+pub struct GptPartitionEntry {
+    pub partition_type_guid: [u8; 16],
+    pub unique_guid: [u8; 16],
+    pub first_lba: u64,
+    pub last_lba: u64,
+    pub attributes: u64,
+}
+
+impl GptPartitionEntry {
+    /// Returns true if this entry doesn't describe a partition (an
+    /// all-zero type GUID).
+    fn is_empty(&self) -> bool {
+        self.partition_type_guid == [0u8; 16]
+    }
+}
+
+/// A block device along with its parsed GPT partition table.
+pub struct BlockDevice {
+    path: PathBuf,
+    partitions: Vec<GptPartitionEntry>,
+}
+
+impl BlockDevice {
+    /// Open `path` and read + validate its GPT header and partition
+    /// entry array.
+    pub fn open(path: &str) -> Result<Self> {
+        let mut file =
+            File::open(path).with_context(|| format!("Failed to open block device {}", path))?;
+
+        let header = GptHeader::read(&mut file)
+            .with_context(|| format!("Failed to read GPT header from {}", path))?;
+        let partitions = header.read_entries(&mut file)?;
+
+        Ok(Self {
+            path: PathBuf::from(path),
+            partitions,
+        })
+    }
+
+    /// Return the 1-indexed partition entry, e.g. `partition(1)` for the
+    /// stateful partition.
+    pub fn partition(&self, number: u32) -> Result<&GptPartitionEntry> {
+        let index = (number as usize)
+            .checked_sub(1)
+            .ok_or_else(|| anyhow!("Partition numbers are 1-indexed"))?;
+
+        match self.partitions.get(index) {
+            Some(entry) if !entry.is_empty() => Ok(entry),
+            _ => Err(HibernateError::StatefulPartitionNotFoundError())
+                .context(format!("No partition {} on {}", number, self.path.display())),
+        }
+    }
+
+    /// Return the device node path for a given partition number, e.g.
+    /// `/dev/sda1` or `/dev/nvme0n1p1`, resolved by walking sysfs for the
+    /// child block device whose `partition` attribute matches `number`.
+    /// This replaces the old heuristic of blindly appending "1" or "p1"
+    /// to the disk path, which guessed wrong on any naming scheme it
+    /// wasn't written for.
+    pub fn partition_path(&self, number: u32) -> Result<String> {
+        partition_device_path(&self.path.to_string_lossy(), number)
+    }
+}
+
+/// The fixed-size portion of a GPT header, as laid out on disk.
+struct GptHeader {
+    partition_entry_lba: u64,
+    num_partition_entries: u32,
+    size_of_partition_entry: u32,
+}
+
+impl GptHeader {
+    fn read(file: &mut (impl Read + Seek)) -> Result<Self> {
+        file.seek(SeekFrom::Start(GPT_HEADER_LBA * SECTOR_SIZE))?;
+        let mut buf = [0u8; SECTOR_SIZE as usize];
+        file.read_exact(&mut buf)?;
+
+        if &buf[0..8] != GPT_SIGNATURE {
+            return Err(anyhow!("Invalid GPT signature"));
+        }
+
+        let header_size = u32::from_le_bytes(buf[12..16].try_into().unwrap()) as usize;
+        if header_size < 92 || header_size > buf.len() {
+            return Err(anyhow!("Invalid GPT header size: {}", header_size));
+        }
+
+        let header_crc = u32::from_le_bytes(buf[16..20].try_into().unwrap());
+        let mut crc_buf = buf;
+        crc_buf[16..20].copy_from_slice(&[0, 0, 0, 0]);
+        if crc32(&crc_buf[..header_size]) != header_crc {
+            return Err(anyhow!("GPT header CRC32 mismatch"));
+        }
+
+        let partition_entry_lba = u64::from_le_bytes(buf[72..80].try_into().unwrap());
+        let num_partition_entries = u32::from_le_bytes(buf[80..84].try_into().unwrap());
+        let size_of_partition_entry = u32::from_le_bytes(buf[84..88].try_into().unwrap());
+        let partition_entry_array_crc = u32::from_le_bytes(buf[88..92].try_into().unwrap());
+
+        let header = Self {
+            partition_entry_lba,
+            num_partition_entries,
+            size_of_partition_entry,
+        };
+        header.verify_entry_array_crc(file, partition_entry_array_crc)?;
+        Ok(header)
+    }
+
+    fn verify_entry_array_crc(
+        &self,
+        file: &mut (impl Read + Seek),
+        expected_crc: u32,
+    ) -> Result<()> {
+        let bytes = self.entry_array_bytes(file)?;
+        if crc32(&bytes) != expected_crc {
+            return Err(anyhow!("GPT partition entry array CRC32 mismatch"));
+        }
+
+        Ok(())
+    }
+
+    fn entry_array_bytes(&self, file: &mut (impl Read + Seek)) -> Result<Vec<u8>> {
+        let len = self.num_partition_entries as usize * self.size_of_partition_entry as usize;
+        let mut bytes = vec![0u8; len];
+        file.seek(SeekFrom::Start(self.partition_entry_lba * SECTOR_SIZE))?;
+        file.read_exact(&mut bytes)?;
+        Ok(bytes)
+    }
+
+    fn read_entries(&self, file: &mut (impl Read + Seek)) -> Result<Vec<GptPartitionEntry>> {
+        let bytes = self.entry_array_bytes(file)?;
+        let entry_size = self.size_of_partition_entry as usize;
+        let mut entries = Vec::with_capacity(self.num_partition_entries as usize);
+        for chunk in bytes.chunks_exact(entry_size) {
+            entries.push(GptPartitionEntry {
+                partition_type_guid: chunk[0..16].try_into().unwrap(),
+                unique_guid: chunk[16..32].try_into().unwrap(),
+                first_lba: u64::from_le_bytes(chunk[32..40].try_into().unwrap()),
+                last_lba: u64::from_le_bytes(chunk[40..48].try_into().unwrap()),
+                attributes: u64::from_le_bytes(chunk[48..56].try_into().unwrap()),
+            });
+        }
+
+        Ok(entries)
+    }
+}
+
+/// Determine the whole-disk block device underlying a partition device
+/// node, e.g. `/dev/sda3` -> `/dev/sda`, or `/dev/nvme0n1p3` ->
+/// `/dev/nvme0n1`, by following sysfs rather than guessing from the
+/// device name.
+pub fn partition_to_disk(partition_path: &str) -> Result<String> {
+    let name = Path::new(partition_path)
+        .file_name()
+        .and_then(|n| n.to_str())
+        .ok_or_else(|| anyhow!("Invalid partition path: {}", partition_path))?;
+
+    let sys_path = format!("/sys/class/block/{}", name);
+    let link = fs::read_link(&sys_path)
+        .with_context(|| format!("Failed to read sysfs link for {}", partition_path))?;
+
+    // The link looks like ".../devices/.../<disk>/<partition>", so the
+    // disk name is the parent directory's file name.
+    let disk_name = link
+        .parent()
+        .and_then(|p| p.file_name())
+        .and_then(|n| n.to_str())
+        .ok_or_else(|| anyhow!("Could not determine parent disk for {}", partition_path))?;
+
+    Ok(format!("/dev/{}", disk_name))
+}
+
+/// Determine the device node for partition `number` on `disk_path` by
+/// walking `/sys/class/block/<disk>/*` for the child block device whose
+/// `partition` attribute equals `number`, the same sysfs-derived source
+/// of truth the kernel itself uses, rather than guessing a "p1"/"1"
+/// suffix from the disk name.
+pub fn partition_device_path(disk_path: &str, number: u32) -> Result<String> {
+    let disk_name = Path::new(disk_path)
+        .file_name()
+        .and_then(|n| n.to_str())
+        .ok_or_else(|| anyhow!("Invalid disk path: {}", disk_path))?;
+
+    let sys_dir = format!("/sys/class/block/{}", disk_name);
+    let entries =
+        fs::read_dir(&sys_dir).with_context(|| format!("Failed to read {}", sys_dir))?;
+
+    for entry in entries.flatten() {
+        let partition_attr = entry.path().join("partition");
+        let contents = match fs::read_to_string(&partition_attr) {
+            Ok(c) => c,
+            Err(_) => continue,
+        };
+
+        if contents.trim().parse::<u32>() == Ok(number) {
+            let child_name = entry.file_name();
+            return Ok(format!("/dev/{}", child_name.to_string_lossy()));
+        }
+    }
+
+    Err(HibernateError::StatefulPartitionNotFoundError()).context(format!(
+        "Partition {} not found under {}",
+        number, sys_dir
+    ))
+}
+
+/// Compute the standard CRC-32 (IEEE 802.3) checksum used by the GPT
+/// spec for header and partition-entry-array integrity checks.
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xFFFF_FFFFu32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xEDB8_8320 & mask);
+        }
+    }
+
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    const TEST_ENTRY_SIZE: usize = 128;
+
+    /// Make a single 128-byte partition entry. A zero `type_guid_byte`
+    /// produces an empty (all-zero type GUID) entry.
+    fn make_entry(type_guid_byte: u8) -> [u8; TEST_ENTRY_SIZE] {
+        let mut entry = [0u8; TEST_ENTRY_SIZE];
+        entry[0] = type_guid_byte;
+        entry
+    }
+
+    /// Build a minimal, valid GPT image (protective MBR sector + header
+    /// + partition entry array) containing the given entries.
+    fn build_image(entries: &[[u8; TEST_ENTRY_SIZE]]) -> Vec<u8> {
+        let partition_entry_lba = 2u64;
+        let mut entry_bytes = Vec::new();
+        for entry in entries {
+            entry_bytes.extend_from_slice(entry);
+        }
+
+        let mut header = vec![0u8; SECTOR_SIZE as usize];
+        header[0..8].copy_from_slice(GPT_SIGNATURE);
+        header[12..16].copy_from_slice(&92u32.to_le_bytes());
+        header[72..80].copy_from_slice(&partition_entry_lba.to_le_bytes());
+        header[80..84].copy_from_slice(&(entries.len() as u32).to_le_bytes());
+        header[84..88].copy_from_slice(&(TEST_ENTRY_SIZE as u32).to_le_bytes());
+        header[88..92].copy_from_slice(&crc32(&entry_bytes).to_le_bytes());
+        // The header CRC itself is computed with the CRC field zeroed.
+        let header_crc = crc32(&header[..92]);
+        header[16..20].copy_from_slice(&header_crc.to_le_bytes());
+
+        let mut image = vec![0u8; SECTOR_SIZE as usize]; // LBA 0: protective MBR.
+        image.extend_from_slice(&header); // LBA 1: GPT header.
+        image.extend_from_slice(&entry_bytes); // LBA 2: partition entries.
+        image
+    }
+
+    #[test]
+    fn parses_valid_header_and_entries() {
+        let image = build_image(&[make_entry(1), make_entry(0)]);
+        let mut cursor = Cursor::new(image);
+        let header = GptHeader::read(&mut cursor).unwrap();
+        let entries = header.read_entries(&mut cursor).unwrap();
+
+        assert_eq!(entries.len(), 2);
+        assert!(!entries[0].is_empty());
+        assert!(entries[1].is_empty());
+        assert_eq!(entries[0].partition_type_guid[0], 1);
+    }
+
+    #[test]
+    fn rejects_bad_signature() {
+        let mut image = build_image(&[make_entry(1)]);
+        image[SECTOR_SIZE as usize] = b'X';
+        let mut cursor = Cursor::new(image);
+
+        assert!(GptHeader::read(&mut cursor).is_err());
+    }
+
+    #[test]
+    fn rejects_corrupted_entry_array() {
+        let mut image = build_image(&[make_entry(1)]);
+        let entry_array_start = 2 * SECTOR_SIZE as usize;
+        image[entry_array_start] ^= 0xFF;
+        let mut cursor = Cursor::new(image);
+
+        assert!(GptHeader::read(&mut cursor).is_err());
+    }
+}
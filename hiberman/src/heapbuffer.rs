@@ -0,0 +1,54 @@
+// Copyright 2021 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Implement a plain heap-backed `Memory` implementation, so code that
+//! only needs a byte buffer (partition discovery, image layout logic)
+//! can be exercised without CAP_IPC_LOCK or a real mmap.
+
+use anyhow::Result;
+
+use crate::memory::Memory;
+
+/// A `Memory` implementation backed by a `Vec<u8>` rather than an mmap
+/// region. Intended for unit tests and anywhere else the mmap-specific
+/// guarantees (page locking, CAP_IPC_LOCK) aren't needed.
+pub struct HeapBuffer {
+    data: Vec<u8>,
+}
+
+impl HeapBuffer {
+    /// Create a new heap buffer of exactly `size` zeroed bytes.
+    pub fn new(size: usize) -> Self {
+        Self {
+            data: vec![0u8; size],
+        }
+    }
+}
+
+impl Memory for HeapBuffer {
+    fn as_slice(&self) -> &[u8] {
+        &self.data
+    }
+
+    fn as_mut_slice(&mut self) -> &mut [u8] {
+        &mut self.data
+    }
+
+    fn len(&self) -> usize {
+        self.data.len()
+    }
+
+    fn fault(&mut self) -> Result<()> {
+        // Heap memory is already resident once allocated; nothing to do.
+        Ok(())
+    }
+
+    fn grow(&mut self, new_len: usize) -> Result<()> {
+        if new_len > self.data.len() {
+            self.data.resize(new_len, 0);
+        }
+
+        Ok(())
+    }
+}
@@ -15,7 +15,10 @@ use anyhow::{Context, Result};
 use log::{error, info, warn};
 use thiserror::Error as ThisError;
 
+use crate::blockdev::{partition_to_disk, BlockDevice};
+use crate::memory::Memory;
 use crate::mmapbuf::MmapBuffer;
+use crate::vglock::{VgLock, NO_AUTO_METADATA_REPAIR_ARGS};
 
 /// Define the number of pages in a larger chunk used to read and write the
 /// hibernate data file.
@@ -56,6 +59,9 @@ pub enum HibernateError {
     /// Key manager error
     #[error("Key manager error: {0}")]
     KeyManagerError(String),
+    /// Failed to lock the stateful VG.
+    #[error("Failed to lock VG: {0}")]
+    LockError(sys_util::Error),
     /// Metadata error
     #[error("Metadata error: {0}")]
     MetadataError(String),
@@ -147,6 +153,9 @@ pub fn path_to_stateful_part() -> Result<String> {
     // device, rather than down from the mount). This is also a test of whether
     // or not we're on an LVM-enabled system. If we fail to get the VG name,
     // this must not be an LVM-enabled system, so just return partition one.
+    // Hold the VG lock across this read so it can't race a concurrent LVM
+    // metadata recovery, same as activate_physical_vg().
+    let _lock = VgLock::acquire()?;
     let partition1 = stateful_block_partition_one()?;
     let vg_name = match get_vg_name(&partition1) {
         Ok(vg) => vg,
@@ -162,11 +171,20 @@ pub fn path_to_stateful_part() -> Result<String> {
 /// partition is running on top of LVM.
 pub fn is_lvm_system() -> Result<bool> {
     let partition1 = stateful_block_partition_one()?;
-    let mut file = File::open(&partition1)?;
-    let mut buffer = MmapBuffer::new(4096)?;
-    let buf = buffer.u8_slice_mut();
-    file.read_exact(buf)
-        .context(format!("Failed to read {}", partition1))?;
+    let file = File::open(&partition1).context(format!("Failed to open {}", partition1))?;
+    let buffer = MmapBuffer::new(4096)?;
+    partition_has_lvm_label(file, buffer)
+}
+
+/// Read the first 4096 bytes out of `source` into `buffer` and check for
+/// the LVM Physical Volume Label magic. Generic over both `Read` and
+/// `Memory` so tests can feed a `Cursor<Vec<u8>>` and a `HeapBuffer`
+/// instead of a real partition device and mmap region.
+fn partition_has_lvm_label(mut source: impl Read, mut buffer: impl Memory) -> Result<bool> {
+    let buf = buffer.as_mut_slice();
+    source
+        .read_exact(buf)
+        .context("Failed to read partition data")?;
     // LVM systems have a Physical Volume Label header that starts with
     // "LABELONE" as its magic. If that's found, this is an LVM system.
     // https://access.redhat.com/documentation/en-us/red_hat_enterprise_linux/4/html/cluster_logical_volume_manager/lvm_metadata
@@ -176,10 +194,9 @@ pub fn is_lvm_system() -> Result<bool> {
     }
 }
 
-/// Look through /proc/mounts to find the block device supporting the
-/// unencrypted stateful partition.
-fn path_to_mounted_stateful_part() -> Result<String> {
-    // Go look through the mounts to see where /mnt/stateful_partition is.
+/// Look through /proc/mounts to find the block device mounted at
+/// `mount_point`.
+fn path_to_mounted_part(mount_point: &str) -> Result<String> {
     let f = File::open("/proc/mounts")?;
     let buf_reader = BufReader::new(f);
     for line in buf_reader.lines().flatten() {
@@ -187,7 +204,7 @@ fn path_to_mounted_stateful_part() -> Result<String> {
         let blk = split.next();
         let path = split.next();
         if let Some(path) = path {
-            if path == "/mnt/stateful_partition" {
+            if path == mount_point {
                 if let Some(blk) = blk {
                     return Ok(blk.to_string());
                 }
@@ -195,31 +212,37 @@ fn path_to_mounted_stateful_part() -> Result<String> {
         }
     }
 
-    Err(HibernateError::StatefulPartitionNotFoundError())
-        .context("Failed to find mounted stateful partition")
+    Err(HibernateError::StatefulPartitionNotFoundError()).context(format!(
+        "Failed to find block device mounted at {}",
+        mount_point
+    ))
+}
+
+/// Look through /proc/mounts to find the block device supporting the
+/// unencrypted stateful partition.
+fn path_to_mounted_stateful_part() -> Result<String> {
+    path_to_mounted_part("/mnt/stateful_partition")
 }
 
-/// Return the path to partition one (stateful) on the root block device.
+/// Return the path to partition one (stateful) on the root block device,
+/// resolved from the GPT partition table rather than guessed by
+/// string-appending "p1"/"1" onto the disk path.
 fn stateful_block_partition_one() -> Result<String> {
     let rootdev = path_to_stateful_block()?;
-    let last = rootdev.chars().last();
-    if let Some(last) = last {
-        if last.is_numeric() {
-            return Ok(format!("{}p1", rootdev));
-        }
-    }
-
-    Ok(format!("{}1", rootdev))
+    let disk = BlockDevice::open(&rootdev)
+        .with_context(|| format!("Failed to read partition table on {}", rootdev))?;
+    disk.partition(1)
+        .context("Stateful partition not found in GPT")?;
+    disk.partition_path(1)
 }
 
 /// Determine the path to the block device containing the stateful partition.
-/// Farm this out to rootdev to keep the magic in one place.
+/// This follows sysfs up from the root filesystem's partition device,
+/// rather than shelling out to rootdev, so it keeps returning the true
+/// root disk even when stateful itself is mounted on a dm-snapshot device.
 pub fn path_to_stateful_block() -> Result<String> {
-    let output = Command::new("/usr/bin/rootdev")
-        .arg("-d")
-        .output()
-        .context("Cannot get rootdev")?;
-    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+    let root_partition = path_to_mounted_part("/")?;
+    partition_to_disk(&root_partition)
 }
 
 /// Get the volume group name for the stateful block device.
@@ -237,44 +260,143 @@ fn is_snapshot_active() -> bool {
     fs::metadata("/dev/mapper/stateful-rw").is_ok()
 }
 
+/// The logical volume hibernate needs activated on the stateful VG.
+const UNENCRYPTED_LV_NAME: &str = "unencrypted";
+
+/// Query LVM's VG-level "autoactivation" metadata property, which newer
+/// LVM versions use to let a VG opt out of being brought up by
+/// `vgchange -aay`/`lvchange -aay`. Returns true if autoactivation is
+/// enabled (the default when the property isn't present), false if it's
+/// explicitly disabled.
+fn vg_autoactivation_enabled(vg_name: &str) -> Result<bool> {
+    let output = Command::new("/sbin/vgs")
+        .args(["--noheadings", "-o", "autoactivation", vg_name])
+        .output()
+        .context("Cannot query VG autoactivation property")?;
+
+    if !output.status.success() {
+        return Err(HibernateError::MetadataError(format!(
+            "vgs -o autoactivation {} failed: {}",
+            vg_name,
+            String::from_utf8_lossy(&output.stderr)
+        )))
+        .context("Cannot query VG autoactivation property");
+    }
+
+    let value = String::from_utf8_lossy(&output.stdout).trim().to_string();
+    Ok(value != "disabled")
+}
+
+/// Query LVM's per-LV "autoactivation" metadata property for a single
+/// `vg_name/lv_name` target. Returns true if autoactivation is enabled
+/// (the default when the property isn't present), false if it's
+/// explicitly disabled.
+fn lv_autoactivation_enabled(target: &str) -> Result<bool> {
+    let output = Command::new("/sbin/lvs")
+        .args(["--noheadings", "-o", "autoactivation", target])
+        .output()
+        .context("Cannot query LV autoactivation property")?;
+
+    if !output.status.success() {
+        return Err(HibernateError::MetadataError(format!(
+            "lvs -o autoactivation {} failed: {}",
+            target,
+            String::from_utf8_lossy(&output.stderr)
+        )))
+        .context("Cannot query LV autoactivation property");
+    }
+
+    let value = String::from_utf8_lossy(&output.stdout).trim().to_string();
+    Ok(value != "disabled")
+}
+
 pub struct ActivatedVolumeGroup {
-    vg_name: Option<String>,
+    // The "vg_name/lv_name" target that was activated by `new`, or None
+    // if it was already active and this guard doesn't own it.
+    target: Option<String>,
+    // Held for the guard's whole lifetime so an interrupted resume never
+    // leaves the VG half-activated alongside a concurrent LVM metadata
+    // recovery. Unused after construction other than via its Drop impl.
+    _lock: VgLock,
 }
 
 impl ActivatedVolumeGroup {
-    fn new(vg_name: String) -> Result<Self> {
+    /// Activate a single logical volume within `vg_name` by name, using
+    /// autoactivation-aware (`-aay`) semantics so a VG or LV that's been
+    /// marked to skip autoactivation stays down. This avoids bringing up
+    /// every LV in the group when only `unencrypted` is needed. Single-LV
+    /// activation is an `lvchange` operation, not a `vgchange` one.
+    ///
+    /// `lock` must already be held by the caller; it's expected to have
+    /// been acquired before the VG metadata used to find `vg_name` was
+    /// even read, so the whole discover-then-activate sequence is
+    /// protected against concurrent metadata recovery.
+    fn new(lock: VgLock, vg_name: String, lv_name: &str) -> Result<Self> {
+        let target = format!("{}/{}", vg_name, lv_name);
+
         // If it already exists, don't reactivate it.
-        if fs::metadata(format!("/dev/{}/unencrypted", vg_name)).is_ok() {
-            return Ok(Self { vg_name: None });
+        if fs::metadata(format!("/dev/{}", target)).is_ok() {
+            return Ok(Self {
+                target: None,
+                _lock: lock,
+            });
         }
 
-        Command::new("/sbin/vgchange")
-            .args(["-ay", &vg_name])
+        if !vg_autoactivation_enabled(&vg_name)? || !lv_autoactivation_enabled(&target)? {
+            return Err(HibernateError::MetadataError(format!(
+                "Autoactivation disabled for {}",
+                target
+            )))
+            .context("Cannot activate volume group");
+        }
+
+        let output = Command::new("/sbin/lvchange")
+            .args(["-aay", &target])
+            .args(NO_AUTO_METADATA_REPAIR_ARGS)
             .output()
-            .context("Cannot activate volume group")?;
+            .context("Cannot activate logical volume")?;
+
+        if !output.status.success() {
+            return Err(HibernateError::MetadataError(format!(
+                "lvchange -aay {} failed: {}",
+                target,
+                String::from_utf8_lossy(&output.stderr)
+            )))
+            .context("Cannot activate logical volume");
+        }
 
         Ok(Self {
-            vg_name: Some(vg_name),
+            target: Some(target),
+            _lock: lock,
         })
     }
 }
 
 impl Drop for ActivatedVolumeGroup {
     fn drop(&mut self) {
-        if let Some(vg_name) = &self.vg_name {
-            let r = Command::new("/sbin/vgchange")
-                .args(["-an", vg_name])
+        if let Some(target) = &self.target {
+            let r = Command::new("/sbin/lvchange")
+                .args(["-an", target])
+                .args(NO_AUTO_METADATA_REPAIR_ARGS)
                 .output();
 
             match r {
-                Ok(_) => {
-                    info!("Deactivated vg {}", vg_name);
+                Ok(output) if output.status.success() => {
+                    info!("Deactivated {}", target);
+                }
+                Ok(output) => {
+                    warn!(
+                        "Failed to deactivate {}: {}",
+                        target,
+                        String::from_utf8_lossy(&output.stderr)
+                    );
                 }
                 Err(e) => {
-                    warn!("Failed to deactivate VG {}: {}", vg_name, e);
+                    warn!("Failed to deactivate {}: {}", target, e);
                 }
             }
         }
+        // _lock releases here, after deactivation has completed.
     }
 }
 
@@ -283,6 +405,12 @@ pub fn activate_physical_vg() -> Result<Option<ActivatedVolumeGroup>> {
         return Ok(None);
     }
 
+    // Acquire the VG lock before the first read of VG metadata (the
+    // pvdisplay lookup in get_vg_name), not just before activation, so
+    // the whole discover-then-activate sequence is protected against a
+    // concurrent LVM metadata recovery.
+    let lock = VgLock::acquire()?;
+
     let partition1 = stateful_block_partition_one()?;
     // Assume that a failure to get the VG name indicates a non-LVM system.
     let vg_name = match get_vg_name(&partition1) {
@@ -292,7 +420,7 @@ pub fn activate_physical_vg() -> Result<Option<ActivatedVolumeGroup>> {
         }
     };
 
-    let vg = ActivatedVolumeGroup::new(vg_name)?;
+    let vg = ActivatedVolumeGroup::new(lock, vg_name, UNENCRYPTED_LV_NAME)?;
     Ok(Some(vg))
 }
 
@@ -329,3 +457,26 @@ fn unlock_process_memory() {
         libc::munlockall();
     };
 }
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::heapbuffer::HeapBuffer;
+
+    #[test]
+    fn lvm_label_detected() {
+        let mut data = vec![0u8; 4096];
+        data[512..520].copy_from_slice(b"LABELONE");
+        let result = partition_has_lvm_label(Cursor::new(data), HeapBuffer::new(4096)).unwrap();
+        assert!(result);
+    }
+
+    #[test]
+    fn non_lvm_partition_not_detected() {
+        let data = vec![0u8; 4096];
+        let result = partition_has_lvm_label(Cursor::new(data), HeapBuffer::new(4096)).unwrap();
+        assert!(!result);
+    }
+}
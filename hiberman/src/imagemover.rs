@@ -0,0 +1,51 @@
+// Copyright 2021 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Implement the page capture loop that copies the hibernate image off
+//! the kernel snapshot device (`/dev/snapshot`) into the staging buffer,
+//! skipping the PFN ranges `PageMap` has classified as free or
+//! zero-filled.
+
+use std::fs::File;
+use std::io::Read;
+use std::io::Seek;
+use std::io::SeekFrom;
+
+use anyhow::{Context, Result};
+
+use crate::hiberutil::get_page_size;
+use crate::memory::Memory;
+use crate::pagemap::PageMap;
+
+/// Copy every live PFN range (per `page_map`) from the snapshot device
+/// into `buffer`, `BUFFER_PAGES` at a time, returning the total number of
+/// bytes captured. Ranges classified as free/zero/slab are skipped
+/// entirely, shrinking both image size and capture time relative to
+/// reading all `total_pfns` pages unconditionally.
+pub fn capture_image(
+    snapshot: &mut File,
+    buffer: &mut impl Memory,
+    page_map: &mut PageMap,
+    total_pfns: u64,
+) -> Result<usize> {
+    let page_size = get_page_size();
+    let mut captured = 0usize;
+
+    for chunk in page_map.snapshot_chunks(total_pfns) {
+        let chunk_bytes = (chunk.end - chunk.start) as usize * page_size;
+        buffer.grow(captured + chunk_bytes)?;
+        let buf = &mut buffer.as_mut_slice()[captured..captured + chunk_bytes];
+
+        snapshot
+            .seek(SeekFrom::Start(chunk.start * page_size as u64))
+            .context("Failed to seek snapshot device")?;
+        snapshot
+            .read_exact(buf)
+            .context("Failed to read snapshot pages")?;
+
+        captured += chunk_bytes;
+    }
+
+    Ok(captured)
+}
@@ -0,0 +1,37 @@
+// Copyright 2021 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Define the `Memory` trait abstracting the snapshot buffer, so the
+//! hibernate/resume read and write paths don't have to hard-code the
+//! mmap-backed implementation.
+
+use anyhow::Result;
+
+/// A page-granular block of memory used to stage the hibernate image as
+/// it's read from or written to disk. `MmapBuffer` is the production
+/// implementation; a plain heap-backed implementation can stand in for
+/// it in unit tests that don't have CAP_IPC_LOCK or want to avoid a real
+/// mmap.
+pub trait Memory {
+    /// Borrow the buffer's contents.
+    fn as_slice(&self) -> &[u8];
+
+    /// Mutably borrow the buffer's contents.
+    fn as_mut_slice(&mut self) -> &mut [u8];
+
+    /// Size of the buffer in bytes.
+    fn len(&self) -> usize;
+
+    /// True if the buffer is zero-sized.
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Touch every page in the buffer, ensuring it's faulted in and
+    /// resident before a time-sensitive read or write loop runs.
+    fn fault(&mut self) -> Result<()>;
+
+    /// Grow the buffer to hold at least `new_len` bytes, in place.
+    fn grow(&mut self, new_len: usize) -> Result<()>;
+}
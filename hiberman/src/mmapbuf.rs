@@ -0,0 +1,108 @@
+// Copyright 2021 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Implement an anonymous mmap-backed buffer used to stage hibernate
+//! image data, sized and aligned in pages so it can be read from and
+//! written to disk with O_DIRECT.
+
+use std::ptr;
+use std::slice;
+
+use anyhow::{Context, Result};
+
+use crate::hiberutil::{get_page_size, HibernateError};
+use crate::memory::Memory;
+
+/// A page-aligned buffer backed by an anonymous mmap region.
+pub struct MmapBuffer {
+    addr: *mut libc::c_void,
+    size: usize,
+}
+
+impl MmapBuffer {
+    /// Create a new mmap buffer at least `size` bytes long, rounded up to
+    /// a whole number of pages.
+    pub fn new(size: usize) -> Result<Self> {
+        let page_size = get_page_size();
+        let size = (size + page_size - 1) / page_size * page_size;
+
+        // Safe because this maps a fresh anonymous region not backed by
+        // any existing memory, and the result is checked below.
+        let addr = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                size,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+                -1,
+                0,
+            )
+        };
+
+        if addr == libc::MAP_FAILED {
+            return Err(HibernateError::MmapError(sys_util::Error::last()))
+                .context("Failed to map buffer");
+        }
+
+        Ok(Self { addr, size })
+    }
+
+    /// Borrow the buffer's contents as a byte slice.
+    pub fn u8_slice(&self) -> &[u8] {
+        // Safe because addr/size describe a live mapping owned by self.
+        unsafe { slice::from_raw_parts(self.addr as *const u8, self.size) }
+    }
+
+    /// Mutably borrow the buffer's contents as a byte slice.
+    pub fn u8_slice_mut(&mut self) -> &mut [u8] {
+        // Safe because addr/size describe a live mapping owned by self.
+        unsafe { slice::from_raw_parts_mut(self.addr as *mut u8, self.size) }
+    }
+}
+
+impl Memory for MmapBuffer {
+    fn as_slice(&self) -> &[u8] {
+        self.u8_slice()
+    }
+
+    fn as_mut_slice(&mut self) -> &mut [u8] {
+        self.u8_slice_mut()
+    }
+
+    fn len(&self) -> usize {
+        self.size
+    }
+
+    fn fault(&mut self) -> Result<()> {
+        let page_size = get_page_size();
+        for page in self.u8_slice_mut().chunks_mut(page_size) {
+            // Safe because this writes within the bounds of the mapping
+            // owned by self, just to fault the page in.
+            unsafe { ptr::write_volatile(&mut page[0], page[0]) };
+        }
+
+        Ok(())
+    }
+
+    fn grow(&mut self, new_len: usize) -> Result<()> {
+        if new_len <= self.size {
+            return Ok(());
+        }
+
+        let mut bigger = Self::new(new_len)?;
+        bigger.u8_slice_mut()[..self.size].copy_from_slice(self.u8_slice());
+        *self = bigger;
+        Ok(())
+    }
+}
+
+impl Drop for MmapBuffer {
+    fn drop(&mut self) {
+        // Safe because addr/size describe a live mapping owned by self,
+        // which is going away.
+        unsafe {
+            libc::munmap(self.addr, self.size);
+        }
+    }
+}
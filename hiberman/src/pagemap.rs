@@ -0,0 +1,299 @@
+// Copyright 2021 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Implement page classification via the kernel's per-PFN flag tables, so
+//! the hibernate image can skip free and zero-filled pages rather than
+//! capturing every page in memory.
+
+use std::fs::File;
+use std::io::ErrorKind;
+use std::io::Read;
+use std::io::Seek;
+use std::io::SeekFrom;
+
+use anyhow::Result;
+use log::warn;
+
+use crate::hiberutil::BUFFER_PAGES;
+
+const KPAGEFLAGS_PATH: &str = "/proc/kpageflags";
+const KPAGECOUNT_PATH: &str = "/proc/kpagecount";
+
+/// Size in bytes of each per-PFN entry in /proc/kpageflags and
+/// /proc/kpagecount.
+const ENTRY_SIZE: u64 = 8;
+
+/// Bit positions within the kpageflags entry, as defined by the kernel's
+/// page-flags ABI (see Documentation/admin-guide/mm/pagemap.rst).
+const KPF_SLAB: u64 = 1 << 7;
+const KPF_BUDDY: u64 = 1 << 12;
+const KPF_NOPAGE: u64 = 1 << 20;
+const KPF_ZERO_PAGE: u64 = 1 << 24;
+
+/// Flags indicating a page is not live data and can be skipped when
+/// capturing the hibernate image: it doesn't exist, is sitting on the
+/// buddy free lists, is the shared zero page, or belongs to the slab
+/// allocator rather than userspace/file-backed data.
+const SKIPPABLE_FLAGS: u64 = KPF_NOPAGE | KPF_BUDDY | KPF_ZERO_PAGE | KPF_SLAB;
+
+/// A half-open `[start, end)` range of physical frame numbers that should
+/// be captured in the hibernate image.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct PfnRange {
+    pub start: u64,
+    pub end: u64,
+}
+
+/// Classifies physical pages as worth saving or safe to skip, based on
+/// `/proc/kpageflags` (and optionally `/proc/kpagecount`). Generic over
+/// the underlying source (rather than hard-coded to `File`) so tests can
+/// substitute an in-memory `Cursor<Vec<u8>>` shaped like the real tables.
+pub struct PageMap<R: Read + Seek = File> {
+    kpageflags: Option<R>,
+    kpagecount: Option<R>,
+}
+
+impl PageMap<File> {
+    /// Open the kernel's page-flag tables. If `/proc/kpageflags` can't be
+    /// opened (non-root, or a kernel that doesn't expose it), the PageMap
+    /// still constructs successfully, but `live_ranges()` will report the
+    /// whole PFN span as live so the caller falls back to capturing every
+    /// page.
+    pub fn new() -> Self {
+        let kpageflags = match File::open(KPAGEFLAGS_PATH) {
+            Ok(f) => Some(f),
+            Err(e) => {
+                warn!(
+                    "Failed to open {}: {}. Capturing all pages.",
+                    KPAGEFLAGS_PATH, e
+                );
+                None
+            }
+        };
+
+        let kpagecount = File::open(KPAGECOUNT_PATH).ok();
+
+        Self {
+            kpageflags,
+            kpagecount,
+        }
+    }
+}
+
+impl<R: Read + Seek> PageMap<R> {
+    /// Construct a `PageMap` from arbitrary sources instead of the real
+    /// `/proc/kpageflags`/`/proc/kpagecount` files, so tests can feed
+    /// hand-built byte buffers.
+    #[cfg(test)]
+    fn from_sources(kpageflags: Option<R>, kpagecount: Option<R>) -> Self {
+        Self {
+            kpageflags,
+            kpagecount,
+        }
+    }
+
+    /// Returns true if page classification data is available.
+    pub fn is_available(&self) -> bool {
+        self.kpageflags.is_some()
+    }
+
+    /// Read the kpageflags (and, if available, kpagecount) entry for a
+    /// single PFN. Returns None on a short read at the end of the file,
+    /// which happens for PFNs beyond the end of physical memory.
+    fn read_entry(&mut self, pfn: u64) -> Result<Option<(u64, u64)>> {
+        let flags = match &mut self.kpageflags {
+            Some(f) => match read_u64_at(f, pfn)? {
+                Some(v) => v,
+                None => return Ok(None),
+            },
+            None => return Ok(None),
+        };
+
+        let mapcount = match &mut self.kpagecount {
+            Some(f) => read_u64_at(f, pfn)?.unwrap_or(1),
+            None => 1,
+        };
+
+        Ok(Some((flags, mapcount)))
+    }
+
+    /// Returns whether the given PFN holds data worth saving in the
+    /// hibernate image.
+    fn is_pfn_live(&mut self, pfn: u64) -> bool {
+        match self.read_entry(pfn) {
+            Ok(Some((flags, mapcount))) => {
+                if flags & SKIPPABLE_FLAGS != 0 {
+                    return false;
+                }
+
+                if mapcount == 0 {
+                    return false;
+                }
+
+                true
+            }
+            // A read error or short read means we don't know anything
+            // about this PFN; be conservative and capture it.
+            _ => true,
+        }
+    }
+
+    /// Walk PFNs `0..pfn_count` and coalesce the live ones into ranges to
+    /// snapshot. If the kpageflags table isn't available, this returns a
+    /// single range covering the whole span, so callers fall back to
+    /// capturing everything.
+    pub fn live_ranges(&mut self, pfn_count: u64) -> Vec<PfnRange> {
+        if !self.is_available() {
+            return vec![PfnRange {
+                start: 0,
+                end: pfn_count,
+            }];
+        }
+
+        let mut ranges = Vec::new();
+        let mut range_start: Option<u64> = None;
+        for pfn in 0..pfn_count {
+            if self.is_pfn_live(pfn) {
+                if range_start.is_none() {
+                    range_start = Some(pfn);
+                }
+            } else if let Some(start) = range_start.take() {
+                ranges.push(PfnRange { start, end: pfn });
+            }
+        }
+
+        if let Some(start) = range_start {
+            ranges.push(PfnRange {
+                start,
+                end: pfn_count,
+            });
+        }
+
+        ranges
+    }
+
+    /// Like `live_ranges()`, but splits the result into chunks no larger
+    /// than `BUFFER_PAGES` so the caller can feed them directly into the
+    /// page-granular buffer loop used to read and write the hibernate
+    /// image.
+    pub fn snapshot_chunks(&mut self, pfn_count: u64) -> Vec<PfnRange> {
+        let mut chunks = Vec::new();
+        for range in self.live_ranges(pfn_count) {
+            let mut start = range.start;
+            while start < range.end {
+                let end = std::cmp::min(start + BUFFER_PAGES as u64, range.end);
+                chunks.push(PfnRange { start, end });
+                start = end;
+            }
+        }
+
+        chunks
+    }
+}
+
+impl Default for PageMap<File> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Seek to the entry for `pfn` and read its little-endian u64 value.
+/// Returns None if the file ends before a full entry can be read.
+/// Generic over `Read + Seek` (rather than hard-coded to `File`) so tests
+/// can feed a `Cursor<Vec<u8>>` shaped like `/proc/kpageflags`.
+fn read_u64_at(file: &mut impl Read + Seek, pfn: u64) -> Result<Option<u64>> {
+    file.seek(SeekFrom::Start(pfn * ENTRY_SIZE))?;
+    let mut buf = [0u8; ENTRY_SIZE as usize];
+    match file.read_exact(&mut buf) {
+        Ok(()) => Ok(Some(u64::from_le_bytes(buf))),
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    /// Build a kpageflags-shaped buffer with one entry per flags value.
+    fn build_kpageflags(flags: &[u64]) -> Cursor<Vec<u8>> {
+        let mut data = Vec::with_capacity(flags.len() * ENTRY_SIZE as usize);
+        for f in flags {
+            data.extend_from_slice(&f.to_le_bytes());
+        }
+        Cursor::new(data)
+    }
+
+    #[test]
+    fn live_ranges_coalesce_contiguous_pages() {
+        // PFNs 2..5 are skippable (buddy free list); the rest are live.
+        let flags = [0, 0, KPF_BUDDY, KPF_BUDDY, KPF_BUDDY, 0, 0, 0, 0, 0];
+        let mut page_map = PageMap::from_sources(Some(build_kpageflags(&flags)), None);
+
+        assert_eq!(
+            page_map.live_ranges(flags.len() as u64),
+            vec![PfnRange { start: 0, end: 2 }, PfnRange { start: 5, end: 10 }]
+        );
+    }
+
+    #[test]
+    fn snapshot_chunks_splits_ranges_at_buffer_pages() {
+        let flags = vec![0u64; BUFFER_PAGES + 8];
+        let mut page_map = PageMap::from_sources(Some(build_kpageflags(&flags)), None);
+
+        assert_eq!(
+            page_map.snapshot_chunks(flags.len() as u64),
+            vec![
+                PfnRange {
+                    start: 0,
+                    end: BUFFER_PAGES as u64,
+                },
+                PfnRange {
+                    start: BUFFER_PAGES as u64,
+                    end: flags.len() as u64,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn live_ranges_without_kpageflags_captures_everything() {
+        let mut page_map: PageMap<Cursor<Vec<u8>>> = PageMap::from_sources(None, None);
+
+        assert!(!page_map.is_available());
+        assert_eq!(
+            page_map.live_ranges(10),
+            vec![PfnRange { start: 0, end: 10 }]
+        );
+    }
+
+    #[test]
+    fn short_read_past_end_of_table_is_treated_as_live() {
+        // Only one entry in the table, but we ask about PFN 1, which falls
+        // past the end of the file.
+        let mut page_map = PageMap::from_sources(Some(build_kpageflags(&[KPF_BUDDY])), None);
+
+        assert_eq!(
+            page_map.live_ranges(2),
+            vec![PfnRange { start: 0, end: 2 }]
+        );
+    }
+
+    #[test]
+    fn zero_mapcount_is_treated_as_not_live() {
+        let flags = [0u64, 0u64];
+        let mapcount = [1u64, 0u64];
+        let mut page_map = PageMap::from_sources(
+            Some(build_kpageflags(&flags)),
+            Some(build_kpageflags(&mapcount)),
+        );
+
+        assert_eq!(
+            page_map.live_ranges(2),
+            vec![PfnRange { start: 0, end: 1 }]
+        );
+    }
+}
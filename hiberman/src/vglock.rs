@@ -0,0 +1,71 @@
+// Copyright 2021 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Implement a lock guarding the stateful volume group against concurrent
+//! LVM operations (in particular automatic metadata recovery) while
+//! hibernate or resume is in the middle of activating or deactivating it.
+
+use std::fs::File;
+use std::fs::OpenOptions;
+use std::os::unix::io::AsRawFd;
+
+use anyhow::{Context, Result};
+use log::warn;
+
+use crate::hiberutil::HibernateError;
+
+/// Well-known lockfile path serializing hiberman's VG activation and
+/// deactivation against itself and any other LVM tooling that honors it.
+const VG_LOCKFILE_PATH: &str = "/run/lock/hiberman-vg.lock";
+
+/// Config override passed to lvchange invocations made while the VG lock
+/// is held, telling LVM not to perform automatic metadata repair. Resume
+/// briefly leaves the VG only partially activated, and a metadata repair
+/// write racing with that window is exactly what corrupts it.
+pub const NO_AUTO_METADATA_REPAIR_ARGS: [&str; 2] =
+    ["--config", "activation/auto_repair_metadata=0"];
+
+/// An RAII guard holding an exclusive, advisory lock on the stateful VG.
+/// While held, no other hiberman instance will activate, deactivate, or
+/// otherwise touch the VG's metadata. Released automatically on drop.
+pub struct VgLock {
+    file: File,
+}
+
+impl VgLock {
+    /// Acquire the VG lock, blocking until it's available.
+    pub fn acquire() -> Result<Self> {
+        // The lockfile's contents are never read or written, only used as
+        // an flock() handle, so explicitly leave it untruncated.
+        let file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(false)
+            .open(VG_LOCKFILE_PATH)
+            .context("Cannot open VG lockfile")?;
+
+        // Safe because flock() only affects file locking state and does
+        // not touch the process's memory.
+        let rc = unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX) };
+        if rc < 0 {
+            return Err(HibernateError::LockError(sys_util::Error::last()))
+                .context("Cannot acquire VG lock");
+        }
+
+        Ok(Self { file })
+    }
+}
+
+impl Drop for VgLock {
+    fn drop(&mut self) {
+        // Safe because flock() only affects file locking state.
+        let rc = unsafe { libc::flock(self.file.as_raw_fd(), libc::LOCK_UN) };
+        if rc < 0 {
+            warn!(
+                "Failed to release VG lock: {}",
+                sys_util::Error::last()
+            );
+        }
+    }
+}